@@ -13,17 +13,20 @@ extern crate winit_types;
 #[macro_use]
 extern crate log;
 
-use parking_lot::Mutex;
+use parking_lot::{Mutex, ReentrantMutex};
 use winit_types::error::Error;
 use winit_types::platform::{OsError, XError, XNotSupported};
 use x11_dl::error::OpenError;
 use x11_dl::xlib::{Display as XDisplay, XErrorEvent};
 
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ffi::CStr;
 use std::mem::MaybeUninit;
 use std::ops::{Deref, DerefMut};
 use std::os::raw;
 use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Weak};
 
 lazy_static! {
@@ -63,7 +66,47 @@ lazy_static! {
     pub static ref DISPLAYS: Mutex<Vec<Weak<Display>>> = Mutex::new(vec![]);
     pub static ref OLD_HANDLERS: Mutex<Vec<unsafe extern "C" fn(_: *mut XDisplay, _: *mut XErrorEvent) -> raw::c_int>> =
         Mutex::new(vec![]);
-    pub static ref LATEST_ERROR: Mutex<Option<Error>> = Mutex::new(None);
+    // Keyed by the raw `*mut Display` pointer (as a `usize`) that produced the
+    // error, so that `Display`s on separate X connections can't see each
+    // other's faults.
+    pub static ref LATEST_ERROR: Mutex<HashMap<usize, Error>> = Mutex::new(HashMap::new());
+    // A `ReentrantMutex` rather than a plain `Mutex`: `x_error_callback` holds
+    // this for the whole time it's running hooks, so that a concurrent error
+    // on another thread (expected, since this crate calls `XInitThreads`)
+    // still sees every registered hook instead of a list emptied out from
+    // under it. Reentrant locking lets a hook that itself calls
+    // `insert_error_hook`, or drops an `ErrorHookGuard`, re-enter from the
+    // same thread without deadlocking.
+    static ref ERROR_HOOKS: ReentrantMutex<RefCell<Vec<(u64, Box<dyn FnMut(*mut XDisplay, *mut XErrorEvent) -> bool + Send>)>>> =
+        ReentrantMutex::new(RefCell::new(vec![]));
+}
+
+static NEXT_ERROR_HOOK_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Registers a hook that is run on every X error, for every connection,
+/// before the error is recorded in the per-connection error slot.
+///
+/// Returning `true` from the hook marks the error as handled, suppressing
+/// the default `LATEST_ERROR` store for it (so `check_errors` won't see it).
+/// Hooks run in registration order. The hook remains registered until the
+/// returned `ErrorHookGuard` is dropped.
+pub fn insert_error_hook(
+    f: impl FnMut(*mut XDisplay, *mut XErrorEvent) -> bool + Send + 'static,
+) -> ErrorHookGuard {
+    let id = NEXT_ERROR_HOOK_ID.fetch_add(1, Ordering::Relaxed);
+    ERROR_HOOKS.lock().borrow_mut().push((id, Box::new(f)));
+    ErrorHookGuard(id)
+}
+
+/// Unregisters its error hook on `Drop`. Returned by [`insert_error_hook`].
+#[derive(Debug)]
+pub struct ErrorHookGuard(u64);
+
+impl Drop for ErrorHookGuard {
+    #[inline]
+    fn drop(&mut self) {
+        ERROR_HOOKS.lock().borrow_mut().retain(|(id, _)| *id != self.0);
+    }
 }
 
 #[macro_export]
@@ -77,10 +120,20 @@ macro_rules! lsyms {
     ($($name:ident),+) => {{( $(lsyms!($name)),+ )}};
 }
 
+/// Which library owns the X11 event queue for a connection, as passed to
+/// `Display::set_event_queue_owner`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventQueueOwner {
+    Xlib,
+    Xcb,
+}
+
 #[derive(Debug)]
 pub struct Display {
     display: *mut x11_dl::xlib::Display,
     owned: bool,
+    event_queue_owner: Mutex<Option<EventQueueOwner>>,
+    synchronous: Mutex<bool>,
 }
 
 unsafe impl Send for Display {}
@@ -96,9 +149,19 @@ impl Eq for Display {}
 impl Display {
     #[inline]
     fn new() -> Result<Arc<Display>, Error> {
+        Self::open(None)
+    }
+
+    /// Opens a connection to the X server named by `name` (in the format
+    /// expected by `XOpenDisplay`, e.g. `"host:0.1"`), or the default
+    /// display (`$DISPLAY`) if `name` is `None`.
+    #[inline]
+    pub fn open(name: Option<&CStr>) -> Result<Arc<Display>, Error> {
         let xlib = lsyms!(XLIB);
         unsafe { (xlib.XInitThreads)() };
-        // FIXME: old handlers...
+        // Any handler displaced here is chained to from `x_error_callback`,
+        // which also re-asserts our handler on every error in case another
+        // library (GTK, SDL, ...) installs its own afterward.
         let old_handler = unsafe { (xlib.XSetErrorHandler)(Some(x_error_callback)) };
 
         match old_handler {
@@ -108,9 +171,25 @@ impl Display {
             _ => (),
         }
 
+        // A named display may already be open under a different pointer
+        // (`XOpenDisplay` allocates a fresh connection every call, even for
+        // an identical name), so check by resolved name *before* opening a
+        // redundant second connection to the same server.
+        if let Some(name) = name {
+            for existing in &*DISPLAYS.lock() {
+                if let Some(existing) = existing.upgrade() {
+                    if existing.name().as_bytes() == name.to_bytes() {
+                        return Ok(existing);
+                    }
+                }
+            }
+        }
+
+        let name_ptr = name.map_or(ptr::null(), |name| name.as_ptr());
+
         // calling XOpenDisplay
         let display = unsafe {
-            let display = (xlib.XOpenDisplay)(ptr::null());
+            let display = (xlib.XOpenDisplay)(name_ptr);
             if display.is_null() {
                 return Err(make_oserror!(OsError::XNotSupported(
                     XNotSupported::XOpenDisplayFailed
@@ -119,9 +198,23 @@ impl Display {
             display
         };
 
+        for existing in &*DISPLAYS.lock() {
+            if let Some(existing) = existing.upgrade() {
+                if existing.display == display {
+                    // The new connection turned out to be redundant; close it
+                    // rather than leaking it before handing back the existing
+                    // `Arc`.
+                    unsafe { (xlib.XCloseDisplay)(display) };
+                    return Ok(existing);
+                }
+            }
+        }
+
         let ret = Arc::new(Display {
             display,
             owned: true,
+            event_queue_owner: Mutex::new(None),
+            synchronous: Mutex::new(false),
         });
 
         DISPLAYS.lock().push(Arc::downgrade(&ret));
@@ -134,6 +227,14 @@ impl Display {
         self.display as *mut _
     }
 
+    /// Returns the display string this connection was opened with, as
+    /// reported by `XDisplayString` (e.g. `":0"` or `"host:0.1"`).
+    #[inline]
+    pub fn name(&self) -> std::borrow::Cow<'_, str> {
+        let xlib = lsyms!(XLIB);
+        unsafe { CStr::from_ptr((xlib.XDisplayString)(self.display)).to_string_lossy() }
+    }
+
     #[inline]
     pub fn from_raw(ndisp: *mut raw::c_void) -> Arc<Display> {
         for display in &*DISPLAYS.lock() {
@@ -147,6 +248,8 @@ impl Display {
         let ret = Arc::new(Display {
             display: ndisp as *mut _,
             owned: false,
+            event_queue_owner: Mutex::new(None),
+            synchronous: Mutex::new(false),
         });
 
         DISPLAYS.lock().push(Arc::downgrade(&ret));
@@ -154,10 +257,11 @@ impl Display {
         ret
     }
 
-    /// Checks whether an error has been triggered by the previous function calls.
+    /// Checks whether an error has been triggered by the previous function calls
+    /// on this display's connection.
     #[inline]
     pub fn check_errors(&self) -> Result<(), Error> {
-        let error = LATEST_ERROR.lock().take();
+        let error = LATEST_ERROR.lock().remove(&(self.display as usize));
         if let Some(error) = error {
             Err(error)
         } else {
@@ -165,10 +269,101 @@ impl Display {
         }
     }
 
-    /// Ignores any previous error.
+    /// Ignores any previous error recorded for this display's connection.
     #[inline]
     pub fn ignore_error(&self) {
-        *LATEST_ERROR.lock() = None;
+        LATEST_ERROR.lock().remove(&(self.display as usize));
+    }
+
+    /// Returns the XCB connection backing this display, obtained via
+    /// `XGetXCBConnection`. This lets Xlib and XCB (or an XCB-based crate
+    /// such as `x11rb`) share a single connection instead of opening a
+    /// second one. The returned pointer is valid for as long as this
+    /// `Arc<Display>`, or any other `Arc<Display>` wrapping the same
+    /// connection, is alive.
+    #[inline]
+    pub fn xcb_connection(&self) -> *mut raw::c_void {
+        let xlib_xcb = lsyms!(XLIB_XCB);
+        unsafe { (xlib_xcb.XGetXCBConnection)(self.display) as *mut raw::c_void }
+    }
+
+    /// Transfers ownership of the event queue between Xlib and XCB via
+    /// `XSetEventQueueOwner`. The transfer only ever happens once per
+    /// connection: since `Display`s are shared (via the `open`/`from_raw`
+    /// dedup caches), two independent call sites can hold the same
+    /// `Arc<Display>` and race to set this, so a repeat call is a no-op
+    /// rather than a crash.
+    ///
+    /// Returns the owner actually in effect once the call returns: `owner`
+    /// on the first call, or whatever was already set on a later one. A
+    /// caller that gets back something other than what it asked for knows
+    /// its requested transfer didn't happen, and why.
+    #[inline]
+    pub fn set_event_queue_owner(&self, owner: EventQueueOwner) -> EventQueueOwner {
+        let mut current = self.event_queue_owner.lock();
+        if let Some(active) = *current {
+            return active;
+        }
+
+        let xlib_xcb = lsyms!(XLIB_XCB);
+        let raw_owner = match owner {
+            EventQueueOwner::Xlib => x11_dl::xlib_xcb::XEventQueueOwner::XlibOwnsEventQueue,
+            EventQueueOwner::Xcb => x11_dl::xlib_xcb::XEventQueueOwner::XCBOwnsEventQueue,
+        };
+        unsafe { (xlib_xcb.XSetEventQueueOwner)(self.display, raw_owner) };
+
+        *current = Some(owner);
+        owner
+    }
+
+    /// Runs `f` with the connection in synchronous mode and returns the
+    /// first error it caused, instead of the usual asynchronous delivery
+    /// where an error from `f` might not surface until a much later
+    /// `check_errors` call.
+    ///
+    /// Any error already pending for this connection is discarded before
+    /// `f` runs, so the result only reflects errors caused by `f` itself.
+    #[inline]
+    pub fn catch_errors<T>(&self, f: impl FnOnce() -> T) -> Result<T, Error> {
+        let xlib = lsyms!(XLIB);
+
+        // Deliver errors from requests issued before `f` now, so that
+        // `ignore_error` really discards them.
+        unsafe { (xlib.XSync)(self.display, 0) };
+        self.ignore_error();
+
+        // A later error for a connection overwrites the one stored before
+        // it, so while `f` runs, any error after the first is marked as
+        // handled, which keeps the first one in the slot.
+        let display_key = self.display as usize;
+        let _keep_first = insert_error_hook(move |_, event| {
+            unsafe { (*event).display as usize == display_key }
+                && LATEST_ERROR.lock().contains_key(&display_key)
+        });
+
+        // `XSynchronize`'s return value is the previous after-function
+        // pointer, not a reusable on/off flag, so the prior state is tracked
+        // here instead (the same pattern `event_queue_owner` uses).
+        let was_synchronous = {
+            let mut synchronous = self.synchronous.lock();
+            let was_synchronous = *synchronous;
+            *synchronous = true;
+            was_synchronous
+        };
+        unsafe { (xlib.XSynchronize)(self.display, 1) };
+
+        let ret = f();
+
+        unsafe { (xlib.XSync)(self.display, 0) };
+        let error = LATEST_ERROR.lock().remove(&(self.display as usize));
+
+        unsafe { (xlib.XSynchronize)(self.display, was_synchronous as raw::c_int) };
+        *self.synchronous.lock() = was_synchronous;
+
+        match error {
+            Some(error) => Err(error),
+            None => Ok(ret),
+        }
     }
 }
 
@@ -180,6 +375,12 @@ impl Drop for Display {
             unsafe { (xlib.XCloseDisplay)(self.display) };
         }
 
+        // The pointer value is about to become eligible for reuse (a later
+        // `open`/`from_raw` can land a new connection at the same address),
+        // so any error still sitting in the per-connection slot must go with
+        // it or a brand-new connection could inherit a stale error.
+        LATEST_ERROR.lock().remove(&(self.display as usize));
+
         // Do some pruning
         let mut displays = DISPLAYS.lock();
 
@@ -195,6 +396,51 @@ unsafe extern "C" fn x_error_callback(
     event: *mut x11_dl::xlib::XErrorEvent,
 ) -> raw::c_int {
     let xlib = lsyms!(XLIB);
+
+    // Another library may have overwritten our handler since it was last
+    // installed; re-assert ourselves and chain to whatever we displace so
+    // errors aren't silently dropped.
+    let displaced = (xlib.XSetErrorHandler)(Some(x_error_callback));
+    if let Some(displaced) = displaced {
+        if displaced != x_error_callback {
+            OLD_HANDLERS.lock().push(displaced);
+        }
+    }
+
+    // Hold the hook registry locked for the whole run, so a concurrent error
+    // on another connection/thread still sees every hook rather than a list
+    // emptied out from under it. The lock is reentrant, so a hook that
+    // itself calls `insert_error_hook`/drops an `ErrorHookGuard` can still
+    // re-enter from this same thread; while a hook runs, its closure is taken
+    // out of its entry (a no-op stands in its place, so the list keeps its
+    // order) so that nested call doesn't need to borrow the closure
+    // currently executing.
+    let hook_registry = ERROR_HOOKS.lock();
+    let ids: Vec<u64> = hook_registry.borrow().iter().map(|(id, _)| *id).collect();
+
+    let mut handled = false;
+    for id in ids {
+        let hook = hook_registry
+            .borrow_mut()
+            .iter_mut()
+            .find(|(hid, _)| *hid == id)
+            .map(|(_, hook)| {
+                std::mem::replace(hook, Box::new(|_: *mut XDisplay, _: *mut XErrorEvent| false))
+            });
+        if let Some(mut hook) = hook {
+            if hook(display_ptr, event) {
+                handled = true;
+            }
+            // If the hook's guard was dropped while it ran, its entry is gone
+            // and the hook is dropped here rather than put back.
+            let mut hooks = hook_registry.borrow_mut();
+            if let Some(entry) = hooks.iter_mut().find(|(hid, _)| *hid == id) {
+                entry.1 = hook;
+            }
+        }
+    }
+    drop(hook_registry);
+
     // `assume_init` is safe here because the array consists of `MaybeUninit` values,
     // which do not require initialization.
     let mut buf: [MaybeUninit<raw::c_char>; 1024] = MaybeUninit::uninit().assume_init();
@@ -213,9 +459,12 @@ unsafe extern "C" fn x_error_callback(
         minor_code: (*event).minor_code,
     }));
 
-    error!("X11 error: {:#?}", error);
-
-    *LATEST_ERROR.lock() = Some(error);
+    if handled {
+        error!("X11 error (handled by hook): {:#?}", error);
+    } else {
+        error!("X11 error: {:#?}", error);
+        LATEST_ERROR.lock().insert((*event).display as usize, error);
+    }
 
     for old_handler in OLD_HANDLERS.lock().iter().rev() {
         old_handler(display_ptr, event);